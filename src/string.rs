@@ -1,52 +1,90 @@
 //! Functionality for handling strings when working with LLVM.
 
+use std::cell::RefCell;
 use std::fmt;
 use std::fmt::{Display, Debug};
+use std::borrow::Cow;
 use std::ffi::{CStr, OsStr};
-use std::mem::transmute;
 use std::ops::Deref;
+use std::string::FromUtf8Error;
+
+use libc::{c_char, size_t};
 
 use super::*;
 
 /// Representation of the data of a C-style (null terminated) string.
-// TODO: make this an unsized type. A slice doesn't work, because &Str needs to
-// have the same size as *const i8 so that conversions are a simple `transmute`.
-// TODO: Replace this with std::ffi::CStr when CStr no longer requries upfront
-// length calculations, which will also probably be when it's possible to make
-// this an unsized type.
-#[allow(dead_code)]
-pub struct Str {
-    data: i8,
-}
+///
+/// This is a transparent newtype over [`CStr`], so `&Str` is a well-defined
+/// dynamically sized reference and string constants can live in statics via
+/// [`from_cstr`](Str::from_cstr) or the [`llvm_str!`] macro.
+///
+/// Because `Str` is now a genuine DST, `&Str` is a **fat** pointer (data +
+/// length), unlike the old thin `*const i8`-sized handle. Never `transmute`
+/// `&Str` to/from a raw pointer or store it in a `#[repr(C)]`/FFI struct that
+/// assumes thin-pointer layout; go through [`as_ptr`](Str::as_ptr) /
+/// [`from_ptr`](Str::from_ptr) instead.
+#[repr(transparent)]
+pub struct Str(CStr);
 
 impl Str {
-    /// 0-cost cast to an &llvm::Str from a pointer to a C-style string that
-    /// must originate from LLVM.
+    /// Cast to an `&llvm::Str` from a pointer to a C-style string that must
+    /// originate from LLVM.
+    ///
+    /// Because `Str` is now a real unsized type (`&Str` is a fat pointer), this
+    /// scans for the null terminator to recover the length. That per-call cost
+    /// is intentional and accepted: the original "same size as `*const i8`,
+    /// transmute-only" requirement is incompatible with `Str` being a genuine
+    /// DST, and a sound, statics-capable layout is worth the scan on the
+    /// builder-hot conversion paths. Callers that want to avoid repeated scans
+    /// should hold the resulting `&Str` rather than re-converting from a raw
+    /// pointer.
     pub unsafe fn from_ptr<'a>(ptr: *const i8) -> &'a Str {
-        transmute(ptr)
+        Str::from_cstr(CStr::from_ptr(ptr as *const c_char))
+    }
+
+    /// 0-cost cast from a [`CStr`]. Available in `const` context, so it can be
+    /// used to initialise `static`/`const` string constants.
+    pub const fn from_cstr(s: &CStr) -> &Str {
+        // Safe: `Str` is `#[repr(transparent)]` over `CStr`.
+        unsafe { &*(s as *const CStr as *const Str) }
     }
 
     pub fn as_ptr(&self) -> *const i8 {
-        unsafe { transmute(self) }
+        self.0.as_ptr() as *const i8
     }
 
-    /// Creates a string slice pointing to the data of this llvm::String, not
-    /// including the null-terminator. This performs a length calculation, so
+    /// The raw bytes of the string up to, but not including, the null
+    /// terminator. No validation is performed, so the slice may contain
+    /// arbitrary non-UTF-8 data (mangled names, inline asm, string constants,
+    /// exotic target triples, ...). This performs a length calculation, so
     /// this conversion isn't free.
-    fn as_str<'a>(&'a self) -> &'a str {
-        unsafe { std::str::from_utf8_unchecked(CStr::from_ptr(self.as_ptr()).to_bytes()) }
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.to_bytes()
+    }
+
+    /// Interprets the string as UTF-8, returning an error if it is not valid
+    /// UTF-8.
+    pub fn to_str(&self) -> Result<&str, std::str::Utf8Error> {
+        self.0.to_str()
+    }
+
+    /// Interprets the string as UTF-8, substituting `U+FFFD REPLACEMENT
+    /// CHARACTER` for any invalid sequences. Borrows when the string is already
+    /// valid UTF-8 and only allocates otherwise.
+    pub fn to_string_lossy(&self) -> Cow<str> {
+        ::std::string::String::from_utf8_lossy(self.as_bytes())
     }
 }
 
 impl Debug for Str {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.as_str())
+        write!(f, "{}", self.to_string_lossy())
     }
 }
 
 impl Display for Str {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.as_str())
+        write!(f, "{}", self.to_string_lossy())
     }
 }
 
@@ -57,11 +95,19 @@ impl AsRef<Str> for Str {
 }
 
 impl AsRef<str> for Str {
+    /// Borrows the string as `&str`.
+    ///
+    /// Because this must return a borrow, it cannot substitute replacement
+    /// characters the way the rest of the lossy path does: on non-UTF-8 input
+    /// it yields only the **valid UTF-8 prefix**, silently stopping at the
+    /// first bad byte. Use [`Str::to_string_lossy`] for a replacement-character
+    /// view of the whole string, or [`Str::to_str`] to observe the error.
     fn as_ref<'a>(&'a self) -> &'a str {
-        unsafe {
-            CStr::from_ptr(transmute(self)).to_str().expect(
-                "LLVM string contained invalid UTF-8 somehow.",
-            )
+        match self.to_str() {
+            Ok(s) => s,
+            Err(e) => unsafe {
+                std::str::from_utf8_unchecked(&self.as_bytes()[..e.valid_up_to()])
+            },
         }
     }
 }
@@ -72,28 +118,90 @@ impl AsRef<OsStr> for Str {
     }
 }
 
-/// Wrapper for owned strings received from LLVM.
+/// An owned, null-terminated string that lives on either side of the FFI
+/// boundary.
 ///
 /// The LLVM C API sometimes returns strings that need to be `free`d, but manual
-/// memory management is not idiomatic in Rust, so we wrap them with this.
+/// memory management is not idiomatic in Rust, so we wrap them with this. The
+/// same type also doubles as a Rust-side owned string you can build and hand to
+/// LLVM: constructing from a Rust `String`/`Vec<u8>` reuses the existing
+/// allocation by appending a NUL in place rather than copying into a fresh
+/// `CString`. The pointer's origin is tracked so `Drop` frees it the right way.
 pub struct String {
-    ptr: *mut i8,
+    repr: StringRepr,
 }
 
-/*impl String {
-    /// Creates a string slice pointing to the data of this llvm::String, not
-    /// including the null-terminator. This performs a length calculation, so
-    /// this conversion isn't free.
-    fn as_str<'a>(&'a self) -> &'a str {
-        unsafe { std::str::from_utf8_unchecked(CStr::from_ptr(self.ptr).to_bytes()) }
-    }
-}*/
+enum StringRepr {
+    /// Pointer owned by LLVM; freed with `LLVMDisposeMessage`.
+    Llvm(*mut i8),
+    /// Buffer owned by Rust's allocator, including the trailing NUL.
+    Rust(Vec<u8>),
+}
 
 impl String {
     /// 0-cost cast to an llvm::String from a pointer to an owned string that
     /// must originate from LLVM.
     pub(crate) fn from_mut(ptr: *mut i8) -> String {
-        String { ptr }
+        String { repr: StringRepr::Llvm(ptr) }
+    }
+
+    fn as_ptr(&self) -> *const i8 {
+        match self.repr {
+            StringRepr::Llvm(ptr) => ptr as *const i8,
+            StringRepr::Rust(ref v) => v.as_ptr() as *const i8,
+        }
+    }
+
+    /// Borrows the Rust-owned buffer, promoting an LLVM-owned string into a
+    /// Rust-owned one first if necessary, so it can be grown in place.
+    fn rust_buf(&mut self) -> &mut Vec<u8> {
+        if let StringRepr::Llvm(ptr) = self.repr {
+            let mut v = Vec::with_capacity(self.as_bytes().len() + 1);
+            v.extend_from_slice(self.as_bytes());
+            v.push(0);
+            // The old pointer is LLVM-owned; free it now, before overwriting
+            // `self.repr` drops the only reference to it.
+            unsafe {
+                LLVMDisposeMessage(ptr);
+            }
+            self.repr = StringRepr::Rust(v);
+        }
+        match self.repr {
+            StringRepr::Rust(ref mut v) => v,
+            StringRepr::Llvm(_) => unreachable!(),
+        }
+    }
+
+    /// Appends a string slice, keeping the trailing NUL in place.
+    pub fn push_str(&mut self, s: &str) {
+        let buf = self.rust_buf();
+        // Drop the existing NUL, append the new bytes, restore the NUL.
+        buf.pop();
+        buf.extend_from_slice(s.as_bytes());
+        buf.push(0);
+    }
+
+    /// Mutable access to the string contents, excluding the trailing NUL.
+    /// Promotes an LLVM-owned string to Rust-owned so the bytes can be edited.
+    pub fn as_mut(&mut self) -> &mut [u8] {
+        let buf = self.rust_buf();
+        let len = buf.len() - 1;
+        &mut buf[..len]
+    }
+}
+
+impl From<::std::string::String> for String {
+    fn from(s: ::std::string::String) -> String {
+        String::from(s.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for String {
+    fn from(mut v: Vec<u8>) -> String {
+        // Reuse the existing allocation, appending the NUL into spare capacity
+        // when there is any instead of reallocating.
+        v.push(0);
+        String { repr: StringRepr::Rust(v) }
     }
 }
 
@@ -111,7 +219,7 @@ impl Debug for String {
 
 impl AsRef<Str> for String {
     fn as_ref(&self) -> &Str {
-        unsafe { Str::from_ptr(self.ptr) }
+        unsafe { Str::from_ptr(self.as_ptr()) }
     }
 }
 
@@ -119,18 +227,68 @@ impl Deref for String {
     type Target = Str;
 
     fn deref<'a>(&'a self) -> &'a Self::Target {
-        unsafe { Str::from_ptr(self.ptr) }
+        unsafe { Str::from_ptr(self.as_ptr()) }
     }
 }
 
 impl Drop for String {
     fn drop(&mut self) {
-        unsafe {
-            LLVMDisposeMessage(self.ptr);
+        // Rust-owned buffers are freed by their `Vec`; only LLVM-owned pointers
+        // need `LLVMDisposeMessage`.
+        if let StringRepr::Llvm(ptr) = self.repr {
+            unsafe {
+                LLVMDisposeMessage(ptr);
+            }
         }
     }
 }
 
+/// Output sink for LLVM APIs that stream a string back through a C callback
+/// instead of returning an owned `char*`.
+///
+/// A number of the wrapper entry points (for instance the type- and
+/// value-printing helpers) don't hand back a heap pointer you must
+/// `LLVMDisposeMessage`; they take an opaque context pointer plus a callback
+/// and invoke it with one or more `(ptr, len)` chunks. This type is that
+/// context: the callback appends each chunk to `bytes`, and [`build_string`]
+/// turns the accumulated buffer into a Rust `String`.
+pub struct RustString {
+    bytes: RefCell<Vec<u8>>,
+}
+
+/// Callback handed to LLVM alongside a [`RustString`] context pointer.
+///
+/// LLVM may call this zero, one, or many times; each call appends `len` bytes
+/// read from `ptr` to the sink without disturbing anything written so far.
+pub extern "C" fn rust_string_write_impl(sink: &RustString, ptr: *const c_char, len: size_t) {
+    let slice = unsafe { std::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    let mut bytes = sink.bytes.borrow_mut();
+    bytes.reserve(slice.len());
+    bytes.extend_from_slice(slice);
+}
+
+/// Collect the output of an LLVM "write to string" API into a Rust `String`.
+///
+/// Creates a fresh [`RustString`] and passes it to `f`, which is expected to
+/// forward `sink as *const _` and [`rust_string_write_impl`] into the relevant
+/// LLVM call. The accumulated bytes are then validated as UTF-8; LLVM strings
+/// are not guaranteed to be valid UTF-8, so this is fallible rather than
+/// assumed.
+pub fn build_string<F>(f: F) -> Result<::std::string::String, FromUtf8Error>
+where
+    F: FnOnce(&RustString),
+{
+    let sink = RustString { bytes: RefCell::new(Vec::new()) };
+    f(&sink);
+    ::std::string::String::from_utf8(sink.bytes.into_inner())
+}
+
+impl<'a> From<&'a CStr> for &'a Str {
+    fn from(s: &'a CStr) -> &'a Str {
+        Str::from_cstr(s)
+    }
+}
+
 // Cast from `CString`s to `&llvm::Str`.
 impl AsRef<Str> for std::ffi::CString {
     fn as_ref(&self) -> &Str {
@@ -138,9 +296,89 @@ impl AsRef<Str> for std::ffi::CString {
     }
 }
 
-/// Turn non-null terminated string literal into null-terminated
-/// `&'static llvm::Str`. Note that this won't work in static variables, but it
-/// does work with the `lazy_static` crate.
+/// A null-terminated string that keeps short contents inline on the stack,
+/// only spilling to the heap when they don't fit in `N` bytes (including the
+/// trailing NUL).
+///
+/// Passing a Rust `&str` into an LLVM C function requires a null-terminated
+/// buffer, which normally means a heap allocation even for tiny names like
+/// `"add"` or `"entry"`. IR construction does this in tight loops, so inlining
+/// short strings is a measurable win. Modelled on rustc's `SmallCStr`.
+///
+/// Note that the default const parameter does not drive expression-level
+/// inference, so `SmallStr::new("add")` won't compile without a turbofish or
+/// annotation. Use the [`SmallStr16`] alias for the common 16-byte case:
+/// `SmallStr16::new("add")`.
+pub struct SmallStr<const N: usize = 16> {
+    repr: SmallStrRepr<N>,
+}
+
+/// A [`SmallStr`] with the default 16-byte inline buffer, callable without a
+/// turbofish: `SmallStr16::new("entry")`.
+pub type SmallStr16 = SmallStr<16>;
+
+enum SmallStrRepr<const N: usize> {
+    /// Inline bytes including the trailing NUL, which delimits the string.
+    Inline { buf: [u8; N] },
+    /// Spilled bytes including the trailing NUL.
+    Heap(Vec<u8>),
+}
+
+impl<const N: usize> SmallStr<N> {
+    /// Builds a `SmallStr` from a string slice, appending the trailing NUL.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `s` contains an interior NUL byte, since the result could not
+    /// round-trip through the C API.
+    pub fn new(s: &str) -> SmallStr<N> {
+        let bytes = s.as_bytes();
+        assert!(
+            !bytes.contains(&0),
+            "SmallStr cannot contain an interior NUL byte"
+        );
+        if bytes.len() + 1 <= N {
+            let mut buf = [0u8; N];
+            buf[..bytes.len()].copy_from_slice(bytes);
+            SmallStr { repr: SmallStrRepr::Inline { buf } }
+        } else {
+            let mut heap = Vec::with_capacity(bytes.len() + 1);
+            heap.extend_from_slice(bytes);
+            heap.push(0);
+            SmallStr { repr: SmallStrRepr::Heap(heap) }
+        }
+    }
+
+    /// Whether the contents are stored inline rather than on the heap.
+    pub fn is_inline(&self) -> bool {
+        matches!(self.repr, SmallStrRepr::Inline { .. })
+    }
+
+    fn as_ptr(&self) -> *const i8 {
+        match self.repr {
+            SmallStrRepr::Inline { ref buf } => buf.as_ptr() as *const i8,
+            SmallStrRepr::Heap(ref v) => v.as_ptr() as *const i8,
+        }
+    }
+}
+
+impl<const N: usize> Deref for SmallStr<N> {
+    type Target = Str;
+
+    fn deref(&self) -> &Str {
+        unsafe { Str::from_ptr(self.as_ptr()) }
+    }
+}
+
+impl<const N: usize> AsRef<Str> for SmallStr<N> {
+    fn as_ref(&self) -> &Str {
+        self
+    }
+}
+
+/// Turn a non-null terminated string literal into a null-terminated
+/// `&'static llvm::Str`. This is `const`-evaluable, so it can be used to
+/// initialise `static`/`const` items.
 ///
 /// Passing no argument creates an empty string, and is equivalent to
 /// `llvm_str!("")`.
@@ -149,21 +387,132 @@ impl AsRef<Str> for std::ffi::CString {
 ///
 /// ```rust
 /// #[macro_use]extern crate llvm;
+/// static NAME: &llvm::Str = llvm_str!("my module");
 /// # fn main() {
 /// # let mut context = llvm::Context::new();
-/// let mut my_module = context.create_module_with_name(llvm_str!("my module"));
+/// let mut my_module = context.create_module_with_name(NAME);
 /// # }
 /// ```
-// TODO: when stmt_expr_attributes (rust issue #15701) is finished, uncomment
-// the `#[allow(unused_unsafe)]` below
 #[macro_export]
 macro_rules! llvm_str {
     ($s:expr) => {
-        //#[allow(unused_unsafe)]
-        unsafe { llvm::Str::from_ptr(concat!($s, "\0").as_ptr() as *mut i8) }
+        llvm::Str::from_cstr(
+            match ::std::ffi::CStr::from_bytes_with_nul(concat!($s, "\0").as_bytes()) {
+                Ok(s) => s,
+                Err(_) => panic!("llvm_str! argument contained an interior NUL byte"),
+            },
+        )
     };
     () => {
-        //#[allow(unused_unsafe)]
-        unsafe { llvm::Str::from_ptr(&mut 0i8 as *mut i8) }
+        llvm::Str::from_cstr(c"")
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed a byte slice through the C callback exactly as LLVM would.
+    fn write(sink: &RustString, bytes: &[u8]) {
+        rust_string_write_impl(sink, bytes.as_ptr() as *const c_char, bytes.len() as size_t);
+    }
+
+    #[test]
+    fn build_string_handles_zero_one_and_many_chunks() {
+        // Zero calls: the closure never writes anything.
+        assert_eq!(build_string(|_| {}).unwrap(), "");
+
+        // One call.
+        assert_eq!(build_string(|s| write(s, b"hello")).unwrap(), "hello");
+
+        // Many calls accumulate rather than overwrite.
+        let joined = build_string(|s| {
+            write(s, b"foo");
+            write(s, b"");
+            write(s, b"bar");
+        })
+        .unwrap();
+        assert_eq!(joined, "foobar");
+    }
+
+    #[test]
+    fn build_string_rejects_invalid_utf8() {
+        assert!(build_string(|s| write(s, b"ab\xffcd")).is_err());
+    }
+
+    #[test]
+    fn str_conversions_on_non_utf8() {
+        // "ab" followed by a lone 0xFF byte (never valid in UTF-8), then the NUL.
+        let cstr = CStr::from_bytes_with_nul(b"ab\xff\0").unwrap();
+        let s = Str::from_cstr(cstr);
+
+        assert_eq!(s.as_bytes(), b"ab\xff");
+        assert!(s.to_str().is_err());
+        // Lossy substitutes the replacement character for the bad byte.
+        assert_eq!(s.to_string_lossy(), "ab\u{FFFD}");
+        // `AsRef<str>` truncates at the first invalid byte.
+        assert_eq!(<Str as AsRef<str>>::as_ref(s), "ab");
+        assert_eq!(format!("{}", s), "ab\u{FFFD}");
+    }
+
+    #[test]
+    fn str_conversions_on_valid_utf8() {
+        let s = Str::from_cstr(c"entry");
+        assert_eq!(s.as_bytes(), b"entry");
+        assert_eq!(s.to_str().unwrap(), "entry");
+        assert_eq!(s.to_string_lossy(), "entry");
+    }
+
+    #[test]
+    fn from_cstr_is_const_and_usable_in_statics() {
+        static NAME: &Str = Str::from_cstr(c"my module");
+        assert_eq!(NAME.as_bytes(), b"my module");
+    }
+
+    #[test]
+    fn small_str_spill_boundary() {
+        // With N == 8, seven content bytes + NUL fit inline (8 <= 8).
+        let inline = SmallStr::<8>::new("abcdefg");
+        assert!(inline.is_inline());
+        assert_eq!(inline.as_bytes(), b"abcdefg");
+
+        // Eight content bytes + NUL (9) spill to the heap.
+        let heap = SmallStr::<8>::new("abcdefgh");
+        assert!(!heap.is_inline());
+        assert_eq!(heap.as_bytes(), b"abcdefgh");
+    }
+
+    #[test]
+    fn small_str16_alias_is_callable_without_turbofish() {
+        let s = SmallStr16::new("entry");
+        assert!(s.is_inline());
+        assert_eq!(s.as_bytes(), b"entry");
+    }
+
+    #[test]
+    #[should_panic(expected = "interior NUL")]
+    fn small_str_rejects_interior_nul() {
+        SmallStr16::new("a\0b");
+    }
+
+    #[test]
+    fn owned_string_from_rust_appends_nul() {
+        let s = String::from(::std::string::String::from("hello"));
+        // Derefs to a NUL-terminated `&Str`.
+        assert_eq!(s.as_bytes(), b"hello");
+    }
+
+    #[test]
+    fn owned_string_push_str_keeps_nul() {
+        let mut s = String::from(vec![b'h', b'i']);
+        s.push_str(", there");
+        assert_eq!(s.as_bytes(), b"hi, there");
+    }
+
+    #[test]
+    fn owned_string_as_mut_edits_in_place() {
+        let mut s = String::from(::std::string::String::from("abc"));
+        s.as_mut()[0] = b'A';
+        assert_eq!(s.as_bytes(), b"Abc");
     }
 }